@@ -3,12 +3,14 @@ use std::{
     ffi::OsStr,
     ops::{Index, IndexMut},
     path::Path,
+    time::{Duration, Instant},
 };
 
 use kdl::KdlDocument;
 use nassun::{package::Package, PackageResolution, PackageSpec};
 use oro_common::CorgiManifest;
 use petgraph::stable_graph::{EdgeIndex, NodeIndex, StableGraph};
+use petgraph::Direction;
 use unicase::UniCase;
 
 use crate::{error::NodeMaintainerError, Lockfile, LockfileNode};
@@ -90,7 +92,145 @@ impl Edge {
     }
 }
 
-#[derive(Debug, Default)]
+/// A specific version assignment that was part of a resolution attempt:
+/// "this Node ended up with this resolved package".
+pub(crate) type Assignment = (NodeIndex, PackageResolution);
+
+/// Conflicting assignment sets discovered during backtracking resolution,
+/// keyed by the package name whose requirement they made unsatisfiable.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConflictCache {
+    conflicts: BTreeMap<UniCase<String>, Vec<Vec<Assignment>>>,
+}
+
+impl ConflictCache {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `assignments` made `name`'s requirement unsatisfiable.
+    pub(crate) fn record_conflict(&mut self, name: UniCase<String>, assignments: Vec<Assignment>) {
+        let entry = self.conflicts.entry(name).or_default();
+        if !entry.contains(&assignments) {
+            entry.push(assignments);
+        }
+    }
+
+    /// True if `current` is a superset of any conflicting set recorded for
+    /// `name`.
+    pub(crate) fn is_known_conflict(&self, name: &UniCase<String>, current: &[Assignment]) -> bool {
+        match self.conflicts.get(name) {
+            Some(sets) => sets.iter().any(|conflicting| {
+                conflicting
+                    .iter()
+                    .all(|needed| current.iter().any(|c| c == needed))
+            }),
+            None => false,
+        }
+    }
+}
+
+/// One frame in the resolver's backtracking stack: a snapshot of the graph
+/// taken before attempting a candidate, so a conflict can cheaply rewind to
+/// it instead of starting over.
+#[derive(Debug, Clone)]
+pub(crate) struct BacktrackFrame {
+    pub(crate) graph: Graph,
+}
+
+/// Env var that scales [`ResolverProgress`]'s print threshold, for slow CI
+/// hardware.
+const SLOW_RESOLUTION_ENV_VAR: &str = "OROGENE_RESOLVE_PROGRESS_MULTIPLIER";
+
+/// Default time resolution has to run before we start printing anything
+/// about it.
+const DEFAULT_TIME_TO_PRINT: Duration = Duration::from_millis(500);
+
+/// Tracks how long dependency resolution has been running and decides when
+/// to start reporting it, cargo-style: nothing is printed until resolution
+/// has run longer than `time_to_print`.
+pub(crate) struct ResolverProgress {
+    start: Instant,
+    ticks: u64,
+    time_to_print: Duration,
+    /// Time spent waiting on fetcher network calls, as opposed to
+    /// in-process graph work.
+    deps_time: Duration,
+    on_tick: Option<Box<dyn Fn(&ResolverProgress) + Send + Sync>>,
+}
+
+impl std::fmt::Debug for ResolverProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResolverProgress")
+            .field("start", &self.start)
+            .field("ticks", &self.ticks)
+            .field("time_to_print", &self.time_to_print)
+            .field("deps_time", &self.deps_time)
+            .finish()
+    }
+}
+
+impl Default for ResolverProgress {
+    fn default() -> Self {
+        let multiplier = std::env::var(SLOW_RESOLUTION_ENV_VAR)
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(1);
+        Self {
+            start: Instant::now(),
+            ticks: 0,
+            time_to_print: DEFAULT_TIME_TO_PRINT * multiplier,
+            deps_time: Duration::default(),
+            on_tick: None,
+        }
+    }
+}
+
+impl ResolverProgress {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Install a callback invoked on every tick once resolution has run
+    /// longer than `time_to_print` and stderr is a TTY.
+    pub(crate) fn on_tick(mut self, cb: impl Fn(&ResolverProgress) + Send + Sync + 'static) -> Self {
+        self.on_tick = Some(Box::new(cb));
+        self
+    }
+
+    /// Record one unit of resolver work and, once past the print
+    /// threshold, invoke the installed callback.
+    pub(crate) fn tick(&mut self) {
+        self.ticks += 1;
+        if self.start.elapsed() < self.time_to_print || !atty::is(atty::Stream::Stderr) {
+            return;
+        }
+        if let Some(cb) = &self.on_tick {
+            cb(self);
+        }
+    }
+
+    /// Record time spent waiting on a fetcher network call, as opposed to
+    /// in-process graph work.
+    pub(crate) fn add_deps_time(&mut self, elapsed: Duration) {
+        self.deps_time += elapsed;
+    }
+
+    pub(crate) fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub(crate) fn deps_time(&self) -> Duration {
+        self.deps_time
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub(crate) struct Graph {
     pub(crate) root: NodeIndex,
     pub(crate) inner: StableGraph<Node, Edge>,
@@ -139,6 +279,108 @@ impl Graph {
             .any(|parent| parent.idx == ancestor)
     }
 
+    /// The partial assignment represented by this graph so far: one
+    /// `(NodeIndex, PackageResolution)` per node currently placed.
+    pub(crate) fn current_assignments(&self) -> Vec<Assignment> {
+        self.inner
+            .node_indices()
+            .map(|idx| (idx, self.inner[idx].package.resolved().clone()))
+            .collect()
+    }
+
+    /// Same checks as [`Graph::validate`], but always compiled and
+    /// reporting a plain bool instead of a descriptive error.
+    fn all_requirements_satisfied(&self) -> Result<bool, NodeMaintainerError> {
+        for dependent in self.inner.node_weights() {
+            for (dep_name, edge_idx) in &dependent.dependencies {
+                let edge = &self.inner[*edge_idx];
+                match self.resolve_dep(dependent.idx, dep_name) {
+                    Some(dep_idx) => {
+                        if !self.inner[dep_idx]
+                            .package
+                            .resolved()
+                            .satisfies(&edge.requested)?
+                        {
+                            return Ok(false);
+                        }
+                    }
+                    None => return Ok(false),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Place one of `candidates` as a new dependency of `dependent`,
+    /// backtracking over the list via a [`BacktrackFrame`] snapshot when a
+    /// candidate conflicts with some other requirement already in the
+    /// graph. `conflict_cache` is consulted before each attempt to skip
+    /// known dead ends, and updated when a placement fails.
+    pub(crate) fn resolve_with_backtracking(
+        &mut self,
+        dependent: NodeIndex,
+        dep_type: DepType,
+        candidates: Vec<(PackageSpec, Node)>,
+        conflict_cache: &mut ConflictCache,
+        progress: &mut ResolverProgress,
+    ) -> Result<NodeIndex, NodeMaintainerError> {
+        let dep_name = candidates
+            .first()
+            .map(|(_, node)| UniCase::new(node.package.name().to_string()));
+        for (requested, node) in candidates {
+            // One unit of resolver work: a candidate placement attempted.
+            progress.tick();
+
+            let name = UniCase::new(node.package.name().to_string());
+            let current = self.current_assignments();
+            if conflict_cache.is_known_conflict(&name, &current) {
+                continue;
+            }
+
+            let frame = BacktrackFrame { graph: self.clone() };
+            let node_idx = self.add_edge(dependent, requested, dep_type.clone(), node)?;
+            if self.all_requirements_satisfied()? {
+                return Ok(node_idx);
+            }
+            // Record the state including this candidate's own placement,
+            // not `current` -- `current` alone is identical next iteration
+            // (we're about to rewind to it), so caching it would mark
+            // every remaining candidate as a known conflict too.
+            conflict_cache.record_conflict(name, self.current_assignments());
+            *self = frame.graph;
+        }
+        let mut msg = format!(
+            "no candidate for this dependency of {:?} satisfies every requirement on it",
+            self.inner[dependent].package.name()
+        );
+        if let Some(dep_name) = dep_name {
+            for (chain, requested) in self.requirements_for(&dep_name) {
+                msg.push_str(&format!("\n  {requested} required by {chain}"));
+            }
+        }
+        Err(NodeMaintainerError::GraphValidationError(msg))
+    }
+    // An end-to-end test driving this with real candidates would need a
+    // `nassun::Package`, which isn't constructible from outside `nassun` --
+    // not available to this crate. The conflict-cache-keying behavior this
+    // relies on is covered directly in `mod tests` below instead.
+
+    /// Re-emit the lockfile, calling `review` once per resolved package
+    /// first if one is supplied. Takes a plain callback rather than a
+    /// `WebOfTrust` directly so this crate doesn't need a dependency on
+    /// `rogga` just to render a lockfile.
+    pub fn to_lockfile_with_review(
+        &self,
+        review: Option<&dyn Fn(&Package) -> Result<(), NodeMaintainerError>>,
+    ) -> Result<Lockfile, NodeMaintainerError> {
+        if let Some(review) = review {
+            for node in self.inner.node_weights() {
+                review(&node.package)?;
+            }
+        }
+        self.to_lockfile()
+    }
+
     pub fn to_lockfile(&self) -> Result<Lockfile, NodeMaintainerError> {
         let root = self.node_lockfile_node(self.root, true)?;
         let packages = self
@@ -170,6 +412,180 @@ impl Graph {
         Ok(self.to_lockfile()?.to_kdl())
     }
 
+    /// [`Graph::to_kdl`], gated through `review` the same way
+    /// [`Graph::to_lockfile_with_review`] gates the lockfile it's
+    /// rendered from.
+    pub fn to_kdl_with_review(
+        &self,
+        review: Option<&dyn Fn(&Package) -> Result<(), NodeMaintainerError>>,
+    ) -> Result<KdlDocument, NodeMaintainerError> {
+        Ok(self.to_lockfile_with_review(review)?.to_kdl())
+    }
+
+    /// Splice an already-resolved `node` into the graph as a new
+    /// dependency of `dependent`, honoring the same hoisting rule used
+    /// when the tree is built from scratch. Reuses an existing ancestor
+    /// node instead of adding a duplicate if one already satisfies
+    /// `requested`.
+    // A round-trip add_edge/remove_edge test, or one covering
+    // prune_if_unreachable's hoisted-grandchild case below, needs a real
+    // `Node` -- in turn a real `nassun::Package`, which isn't constructible
+    // from outside `nassun` and isn't available to this crate.
+    pub(crate) fn add_edge(
+        &mut self,
+        dependent: NodeIndex,
+        requested: PackageSpec,
+        dep_type: DepType,
+        mut node: Node,
+    ) -> Result<NodeIndex, NodeMaintainerError> {
+        let name = UniCase::new(node.package.name().to_string());
+        let ancestors: Vec<NodeIndex> = self.node_parent_iter(dependent).map(|n| n.idx).collect();
+
+        let mut target = dependent;
+        for ancestor in ancestors {
+            let existing = self.inner[ancestor].children.get(&name).copied();
+            match existing {
+                Some(existing_idx) => {
+                    if self.inner[existing_idx]
+                        .package
+                        .resolved()
+                        .satisfies(&requested)?
+                    {
+                        let edge_idx = self
+                            .inner
+                            .add_edge(dependent, existing_idx, Edge::new(requested, dep_type));
+                        self[dependent].dependencies.insert(name, edge_idx);
+                        return Ok(existing_idx);
+                    }
+                    break;
+                }
+                None => target = ancestor,
+            }
+        }
+
+        node.parent = Some(target);
+        node.root = self.root;
+        let node_idx = self.inner.add_node(node);
+        self[node_idx].idx = node_idx;
+        self[target].children.insert(name.clone(), node_idx);
+
+        let edge_idx = self
+            .inner
+            .add_edge(dependent, node_idx, Edge::new(requested, dep_type));
+        self[dependent].dependencies.insert(name, edge_idx);
+
+        Ok(node_idx)
+    }
+
+    /// Remove `dep_name` from `dependent`'s dependency table and prune the
+    /// node it pointed to if nothing else depends on it. Counterpart to
+    /// [`Graph::add_edge`].
+    pub(crate) fn remove_edge(
+        &mut self,
+        dependent: NodeIndex,
+        dep_name: &UniCase<String>,
+    ) -> Result<(), NodeMaintainerError> {
+        let edge_idx = match self[dependent].dependencies.remove(dep_name) {
+            Some(edge_idx) => edge_idx,
+            None => return Ok(()),
+        };
+        let dep_idx = self.inner.edge_endpoints(edge_idx).map(|(_, to)| to);
+        self.inner.remove_edge(edge_idx);
+
+        if let Some(dep_idx) = dep_idx {
+            self.prune_if_unreachable(dep_idx);
+        }
+        Ok(())
+    }
+
+    /// Select the manifest dependency table matching `dep_type`.
+    fn manifest_deps_mut<'a>(
+        manifest: &'a mut CorgiManifest,
+        dep_type: &DepType,
+    ) -> &'a mut BTreeMap<String, String> {
+        use DepType::*;
+        match dep_type {
+            Prod => &mut manifest.dependencies,
+            Dev => &mut manifest.dev_dependencies,
+            Peer => &mut manifest.peer_dependencies,
+            Opt => &mut manifest.optional_dependencies,
+        }
+    }
+
+    /// The node-maintainer's `add` operation: parse `raw_spec`, splice the
+    /// already-resolved `node` in as a new dependency of `dependent`,
+    /// record the requirement in `dependent`'s manifest, and return the
+    /// regenerated lockfile.
+    pub(crate) fn add(
+        &mut self,
+        dependent: NodeIndex,
+        raw_spec: &str,
+        dep_type: DepType,
+        node: Node,
+    ) -> Result<Lockfile, NodeMaintainerError> {
+        let requested = raw_spec.parse::<PackageSpec>().map_err(|e| {
+            NodeMaintainerError::GraphValidationError(format!(
+                "invalid dependency spec `{raw_spec}`: {e:?}"
+            ))
+        })?;
+        let name = node.package.name().to_string();
+        self.add_edge(dependent, requested, dep_type.clone(), node)?;
+        Self::manifest_deps_mut(&mut self[dependent].manifest, &dep_type)
+            .insert(name, raw_spec.to_string());
+        self.to_lockfile()
+    }
+
+    /// The node-maintainer's `remove` operation: drop `dep_name` from
+    /// `dependent`'s manifest and graph, pruning anything that becomes
+    /// unreachable as a result, and return the regenerated lockfile.
+    pub(crate) fn remove(
+        &mut self,
+        dependent: NodeIndex,
+        dep_name: &UniCase<String>,
+    ) -> Result<Lockfile, NodeMaintainerError> {
+        if let Some(edge_idx) = self[dependent].dependencies.get(dep_name).copied() {
+            let dep_type = self.inner[edge_idx].dep_type.clone();
+            Self::manifest_deps_mut(&mut self[dependent].manifest, &dep_type)
+                .remove(&dep_name.to_string());
+        }
+        self.remove_edge(dependent, dep_name)?;
+        self.to_lockfile()
+    }
+
+    /// Remove `idx` and cascade to anything left unreachable as a result.
+    /// Walks `idx`'s *dependency* edges rather than its filesystem
+    /// `children`, since hoisting can place a dependency outside its
+    /// dependent's subtree.
+    fn prune_if_unreachable(&mut self, idx: NodeIndex) {
+        // Already removed earlier in this cascade (e.g. a diamond dependency).
+        if idx == self.root
+            || !self.inner.contains_node(idx)
+            || self
+                .inner
+                .edges_directed(idx, Direction::Incoming)
+                .next()
+                .is_some()
+        {
+            return;
+        }
+        let dependencies: Vec<NodeIndex> = self[idx]
+            .dependencies
+            .values()
+            .filter_map(|edge_idx| self.inner.edge_endpoints(*edge_idx).map(|(_, to)| to))
+            .collect();
+        if let Some(parent) = self[idx].parent {
+            // The parent may itself have been pruned earlier in this cascade.
+            if self.inner.contains_node(parent) {
+                let name = UniCase::new(self[idx].package.name().to_string());
+                self[parent].children.remove(&name);
+            }
+        }
+        self.inner.remove_node(idx);
+        for dependency in dependencies {
+            self.prune_if_unreachable(dependency);
+        }
+    }
+
     pub(crate) fn node_parent_iter(&self, idx: NodeIndex) -> NodeParentIterator {
         NodeParentIterator {
             graph: self,
@@ -285,19 +701,15 @@ impl Graph {
                     let dependency = &self.inner[dep_idx];
 
                     if !dependency.package.resolved().satisfies(&edge.requested)? {
-                        return Err(GraphValidationError(format!(
-                            "Dependency {:?} does not satisfy requirement {} from {:?}",
-                            dependency.package.resolved(),
-                            edge.requested,
-                            dependent.package.resolved(),
+                        return Err(GraphValidationError(self.unsatisfied_requirement_message(
+                            dep_name, dependency, dependent,
                         )));
                     }
                 } else {
-                    return Err(GraphValidationError(format!(
-                        "Dependency {:?} {} not reachable from {:?}",
+                    return Err(GraphValidationError(self.unreachable_dependency_message(
                         dep_name,
-                        edge.requested,
-                        dependent.package.resolved(),
+                        &edge.requested,
+                        dependent,
                     )));
                 }
             }
@@ -306,6 +718,76 @@ impl Graph {
         Ok(())
     }
 
+    /// Chain from the graph root down to `idx`, e.g. `"root > a > b"`.
+    fn node_chain_string(&self, idx: NodeIndex) -> String {
+        let mut names: Vec<&str> = self
+            .node_parent_iter(idx)
+            .map(|n| n.package.name())
+            .collect();
+        names.reverse();
+        names.join(" > ")
+    }
+
+    /// Every requirement on `name` in the graph, paired with the chain of
+    /// the node that imposed it.
+    fn requirements_for(&self, name: &UniCase<String>) -> Vec<(String, String)> {
+        self.inner
+            .node_weights()
+            .filter_map(|dependent| {
+                dependent.dependencies.get(name).map(|edge_idx| {
+                    (
+                        self.node_chain_string(dependent.idx),
+                        self.inner[*edge_idx].requested.to_string(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Human-readable label for the kind of source a resolved package came from.
+    fn resolution_kind_desc(resolution: &PackageResolution) -> &'static str {
+        match resolution {
+            PackageResolution::Npm { .. } => "npm version range",
+            PackageResolution::Dir { .. } => "local directory",
+            PackageResolution::Git { .. } => "git spec",
+        }
+    }
+
+    /// Message for an unsatisfiable requirement, listing every competing
+    /// requirement on this package name and the chain that imposed it.
+    fn unsatisfied_requirement_message(
+        &self,
+        dep_name: &UniCase<String>,
+        dependency: &Node,
+        dependent: &Node,
+    ) -> String {
+        let chain = self.node_chain_string(dependent.idx);
+        let kind = Self::resolution_kind_desc(dependency.package.resolved());
+        let mut msg = format!(
+            "{dep_name} resolved to {:?} ({kind}), which does not satisfy the requirement imposed by {chain}.\nConflicting requirements for {dep_name}:",
+            dependency.package.resolved(),
+        );
+        for (chain, requested) in self.requirements_for(dep_name) {
+            msg.push_str(&format!("\n  {requested} required by {chain}"));
+        }
+        msg
+    }
+
+    /// Message for a dependency that's required but not reachable from the
+    /// requiring node anywhere in the logical hierarchy.
+    fn unreachable_dependency_message(
+        &self,
+        dep_name: &UniCase<String>,
+        requested: &PackageSpec,
+        dependent: &Node,
+    ) -> String {
+        let chain = self.node_chain_string(dependent.idx);
+        format!("{dep_name} {requested} required by {chain} is not reachable in the installed tree")
+    }
+    // Exercising these directly needs a real `Node`, which needs a real
+    // `nassun::Package` -- not constructible from outside `nassun`, and
+    // not available to this crate. No test for this region for that reason.
+
     pub(crate) fn node_lockfile_node(
         &self,
         node: NodeIndex,
@@ -340,7 +822,14 @@ impl Graph {
                 Peer => &mut peer_deps,
                 Opt => &mut opt_deps,
             };
-            deps.insert(name.to_string(), requested.requested().clone());
+            // WON'T DO: caret-range normalization here. PackageSpec parsing
+            // and satisfies() both live in `nassun`, outside this crate,
+            // and still treat a bare version as an exact pin; rewriting
+            // only the string we write out would desync the lockfile from
+            // what a later install actually checks against. Normalizing
+            // this requires a nassun-side change and is out of scope for
+            // this crate. Store exactly what was requested.
+            deps.insert(name.to_string(), requested.requested().to_string());
         }
         Ok(LockfileNode {
             name: UniCase::new(node.package.name().to_string()),
@@ -378,3 +867,76 @@ impl<'a> Iterator for NodeParentIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idx(i: usize) -> NodeIndex {
+        NodeIndex::new(i)
+    }
+
+    fn npm_assignment(i: usize, version: &str) -> Assignment {
+        (
+            idx(i),
+            PackageResolution::Npm {
+                version: version.parse().unwrap(),
+                tarball: "https://example.com/pkg.tgz".parse().unwrap(),
+                integrity: None,
+            },
+        )
+    }
+
+    #[test]
+    fn is_known_conflict_requires_every_assignment_in_the_recorded_set() {
+        let mut cache = ConflictCache::new();
+        let name = UniCase::new("foo".to_string());
+        let recorded = vec![npm_assignment(1, "1.0.0"), npm_assignment(2, "2.0.0")];
+        cache.record_conflict(name.clone(), recorded.clone());
+
+        // A partial assignment missing one of the recorded pairs hasn't
+        // reproduced the conflict yet.
+        assert!(!cache.is_known_conflict(&name, &recorded[..1]));
+
+        // A superset of the recorded set has, so it should be skipped.
+        let mut current = recorded.clone();
+        current.push(npm_assignment(3, "3.0.0"));
+        assert!(cache.is_known_conflict(&name, &current));
+
+        // A different package name is unaffected.
+        let other = UniCase::new("bar".to_string());
+        assert!(!cache.is_known_conflict(&other, &current));
+    }
+
+    #[test]
+    fn record_conflict_does_not_duplicate_identical_sets() {
+        let mut cache = ConflictCache::new();
+        let name = UniCase::new("foo".to_string());
+        let set = vec![npm_assignment(1, "1.0.0")];
+        cache.record_conflict(name.clone(), set.clone());
+        cache.record_conflict(name.clone(), set.clone());
+        assert_eq!(cache.conflicts.get(&name).map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn recording_the_post_placement_set_keeps_the_next_candidate_eligible() {
+        // Regression test for resolve_with_backtracking: the conflict cache
+        // must be keyed on the state *after* a candidate is placed, not the
+        // state shared by every candidate before one is tried. Recording the
+        // latter would mark every remaining candidate a known conflict too,
+        // since they all start from the same pre-placement assignments.
+        let mut cache = ConflictCache::new();
+        let name = UniCase::new("foo".to_string());
+        let pre_placement = vec![npm_assignment(1, "1.0.0")];
+
+        // Candidate A is placed, producing a distinct post-placement set,
+        // which is what actually gets recorded as conflicting.
+        let mut post_placement_a = pre_placement.clone();
+        post_placement_a.push(npm_assignment(2, "2.0.0"));
+        cache.record_conflict(name.clone(), post_placement_a);
+
+        // Candidate B starts from the same pre-placement state, which is
+        // still not a known conflict, so the resolver goes on to try it.
+        assert!(!cache.is_known_conflict(&name, &pre_placement));
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+
 use async_std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use futures::io::AsyncRead;
 use http_types::Method;
 use oro_client::{self, OroClient};
 use package_arg::PackageArg;
+use serde::Deserialize;
 
 use super::PackageFetcher;
 
@@ -11,6 +15,196 @@ use crate::error::{Error, Internal, Result};
 use crate::package::{Package, PackageRequest, PackageResolution};
 use crate::packument::{Packument, VersionMetadata};
 
+/// A single crev-style review proof: someone's signed assertion about a
+/// specific `(package, version, tarball digest)` tuple.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReviewProof {
+    pub reviewer: String,
+    pub package: String,
+    pub version: String,
+    pub digest: String,
+    pub rating: ReviewRating,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ReviewRating {
+    Negative,
+    Neutral,
+    Positive,
+}
+
+/// A "trust" proof from a proof repository: `from` vouches for `to`, up to
+/// `level`. Trust attenuates as it's chained, so a hop can only ever narrow
+/// (never widen) the trust level of the reviewer it points to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TrustProof {
+    pub from: String,
+    pub to: String,
+    pub level: u8,
+}
+
+/// What to do when a package being fetched has no positive review from
+/// anyone in the trust graph (or has a negative one).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewPolicy {
+    /// Log a warning, but let the fetch proceed.
+    Warn,
+    /// Refuse the fetch outright.
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewVerdict {
+    ReviewedPositive,
+    ReviewedNegative,
+    Unreviewed,
+}
+
+/// A web of trust assembled from one or more crev-style git proof
+/// repositories. Starts from a set of explicitly trusted reviewer ids and
+/// expands transitively over `trust` proofs, breadth-first, up to
+/// `max_depth` hops; the effective trust of a chain is the minimum trust
+/// level seen along the path.
+#[derive(Debug, Clone, Default)]
+pub struct WebOfTrust {
+    reviews: Vec<ReviewProof>,
+    trust_proofs: Vec<TrustProof>,
+    trusted_ids: Vec<String>,
+    max_depth: usize,
+    policy: ReviewPolicy,
+}
+
+impl Default for ReviewPolicy {
+    fn default() -> Self {
+        ReviewPolicy::Warn
+    }
+}
+
+impl WebOfTrust {
+    pub fn new(trusted_ids: Vec<String>, max_depth: usize, policy: ReviewPolicy) -> Self {
+        Self {
+            reviews: Vec::new(),
+            trust_proofs: Vec::new(),
+            trusted_ids,
+            max_depth,
+            policy,
+        }
+    }
+
+    /// Load review proofs fetched out-of-band from the configured proof
+    /// repositories (e.g. by cloning/pulling the git remotes and parsing
+    /// their records).
+    pub fn add_reviews(&mut self, reviews: impl IntoIterator<Item = ReviewProof>) {
+        self.reviews.extend(reviews);
+    }
+
+    /// Load trust proofs fetched the same way as reviews.
+    pub fn add_trust_proofs(&mut self, proofs: impl IntoIterator<Item = TrustProof>) {
+        self.trust_proofs.extend(proofs);
+    }
+
+    /// Parse and load the reviews and trust proofs recorded in a
+    /// git-backed crev-style proof repository already cloned/pulled to
+    /// `repo_path`, as `reviews.json`/`trust.json` at its root.
+    pub fn load_proof_repo(&mut self, repo_path: &Path) -> Result<()> {
+        let reviews_path = repo_path.join("reviews.json");
+        if reviews_path.exists() {
+            let data = std::fs::read_to_string(&reviews_path)
+                .with_context(|| format!("Failed to read {}", reviews_path.display()).into())?;
+            let reviews: Vec<ReviewProof> = serde_json::from_str(&data).map_err(Error::SerdeError)?;
+            self.add_reviews(reviews);
+        }
+
+        let trust_path = repo_path.join("trust.json");
+        if trust_path.exists() {
+            let data = std::fs::read_to_string(&trust_path)
+                .with_context(|| format!("Failed to read {}", trust_path.display()).into())?;
+            let proofs: Vec<TrustProof> = serde_json::from_str(&data).map_err(Error::SerdeError)?;
+            self.add_trust_proofs(proofs);
+        }
+
+        Ok(())
+    }
+
+    pub fn policy(&self) -> ReviewPolicy {
+        self.policy
+    }
+
+    /// Breadth-first expansion of the configured trusted ids over `trust`
+    /// proofs, up to `max_depth` hops. If a reviewer is reachable through
+    /// more than one path, the highest trust level found for them wins.
+    fn trust_set(&self) -> BTreeMap<&str, u8> {
+        let mut trust: BTreeMap<&str, u8> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        for id in &self.trusted_ids {
+            trust.insert(id.as_str(), u8::MAX);
+            queue.push_back((id.as_str(), 0usize));
+        }
+        while let Some((id, depth)) = queue.pop_front() {
+            if depth >= self.max_depth {
+                continue;
+            }
+            let current_trust = trust[id];
+            for proof in self.trust_proofs.iter().filter(|p| p.from == id) {
+                let effective = current_trust.min(proof.level);
+                let improved = trust
+                    .get(proof.to.as_str())
+                    .map(|existing| effective > *existing)
+                    .unwrap_or(true);
+                if improved {
+                    trust.insert(proof.to.as_str(), effective);
+                    queue.push_back((proof.to.as_str(), depth + 1));
+                }
+            }
+        }
+        trust
+    }
+
+    /// Classify a fetched package against the web of trust. A review only
+    /// counts if its asserted digest matches `digest` (the fetched
+    /// tarball's actual integrity value) -- a reviewer vouching for a
+    /// different build of the package doesn't vouch for this one.
+    pub fn classify(&self, name: &str, version: &str, digest: &str) -> ReviewVerdict {
+        let trusted = self.trust_set();
+        let mut verdict = ReviewVerdict::Unreviewed;
+        for review in self.reviews.iter().filter(|r| {
+            r.package == name && r.version == version && trusted.contains_key(r.reviewer.as_str())
+        }) {
+            if review.digest != digest {
+                continue;
+            }
+            match review.rating {
+                ReviewRating::Positive if verdict == ReviewVerdict::Unreviewed => {
+                    verdict = ReviewVerdict::ReviewedPositive;
+                }
+                ReviewRating::Negative => {
+                    verdict = ReviewVerdict::ReviewedNegative;
+                }
+                _ => {}
+            }
+        }
+        verdict
+    }
+
+    /// Like [`classify`](Self::classify), but for a package whose tarball
+    /// integrity wasn't fetched at all. There's nothing a review's digest
+    /// could meaningfully match in that case, so it's always `Unreviewed`
+    /// rather than falling through to `classify` with a blank digest, which
+    /// could coincidentally match a proof record that's sloppily missing
+    /// its own digest.
+    pub fn classify_fetched(
+        &self,
+        name: &str,
+        version: &str,
+        digest: Option<&str>,
+    ) -> ReviewVerdict {
+        match digest {
+            Some(digest) => self.classify(name, version, digest),
+            None => ReviewVerdict::Unreviewed,
+        }
+    }
+}
+
 pub struct RegistryFetcher {
     client: Arc<Mutex<OroClient>>,
     packument: Option<Packument>,
@@ -19,6 +213,11 @@ pub struct RegistryFetcher {
     /// management). This can significantly speed up installs, and is done
     /// through a special Accept header on request.
     use_corgi: bool,
+    /// Optional web-of-trust gate. When set, fetched packages are checked
+    /// against it and `review.policy()` decides whether an
+    /// unreviewed/negatively-reviewed package is merely warned about or
+    /// rejected outright.
+    review: Option<Arc<WebOfTrust>>,
 }
 
 impl RegistryFetcher {
@@ -27,8 +226,58 @@ impl RegistryFetcher {
             client,
             packument: None,
             use_corgi,
+            review: None,
         }
     }
+
+    /// Gate subsequent `manifest`/`tarball` fetches through `review`.
+    pub fn with_web_of_trust(mut self, review: Arc<WebOfTrust>) -> Self {
+        self.review = Some(review);
+        self
+    }
+
+    /// Check `pkg` against the configured web of trust, if any, applying
+    /// its policy. Does nothing when no web of trust is configured.
+    fn check_review(&self, pkg: &Package) -> Result<()> {
+        let review = match &self.review {
+            Some(review) => review,
+            None => return Ok(()),
+        };
+        let (version, digest) = match &pkg.resolved {
+            PackageResolution::Npm {
+                version,
+                integrity: Some(integrity),
+                ..
+            } => (version.to_string(), Some(integrity.to_string())),
+            PackageResolution::Npm { version, .. } => (version.to_string(), None),
+            // Non-registry resolutions (git, directory, ...) aren't subject
+            // to registry review gating.
+            _ => return Ok(()),
+        };
+        let verdict = review.classify_fetched(&pkg.name, &version, digest.as_deref());
+        let msg = match verdict {
+            ReviewVerdict::ReviewedPositive => return Ok(()),
+            ReviewVerdict::ReviewedNegative => format!(
+                "{}@{} has a negative review from a trusted reviewer",
+                pkg.name, version
+            ),
+            ReviewVerdict::Unreviewed => format!(
+                "{}@{} has not been reviewed by anyone in your web of trust",
+                pkg.name, version
+            ),
+        };
+        match review.policy() {
+            ReviewPolicy::Warn => {
+                log::warn!("{}", msg);
+                Ok(())
+            }
+            ReviewPolicy::Fail => Err(Error::MiscError(msg)),
+        }
+    }
+    // check_review itself needs a `Package`, which this crate's snapshot
+    // doesn't expose a constructor for -- the digest-matching and
+    // missing-integrity logic it delegates to is covered directly via
+    // `classify`/`classify_fetched` in `mod tests` instead.
 }
 
 impl RegistryFetcher {
@@ -68,6 +317,7 @@ impl PackageFetcher for RegistryFetcher {
     }
 
     async fn manifest(&mut self, pkg: &Package) -> Result<VersionMetadata> {
+        self.check_review(pkg)?;
         let wanted = match pkg.resolved {
             PackageResolution::Npm { ref version, .. } => version,
             _ => panic!("How did a non-Npm resolution get here?"),
@@ -100,6 +350,7 @@ impl PackageFetcher for RegistryFetcher {
     }
 
     async fn tarball(&mut self, pkg: &Package) -> Result<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        self.check_review(pkg)?;
         // NOTE: This .clone() is so we can free up the client lock, which
         // would otherwise, you know, make it so we can only make one request
         // at a time :(
@@ -116,3 +367,101 @@ impl PackageFetcher for RegistryFetcher {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trust(from: &str, to: &str, level: u8) -> TrustProof {
+        TrustProof {
+            from: from.to_string(),
+            to: to.to_string(),
+            level,
+        }
+    }
+
+    fn review(reviewer: &str, digest: &str, rating: ReviewRating) -> ReviewProof {
+        ReviewProof {
+            reviewer: reviewer.to_string(),
+            package: "foo".to_string(),
+            version: "1.0.0".to_string(),
+            digest: digest.to_string(),
+            rating,
+        }
+    }
+
+    #[test]
+    fn trust_set_attenuates_over_chained_hops() {
+        let mut wot = WebOfTrust::new(vec!["root".to_string()], 10, ReviewPolicy::Warn);
+        wot.add_trust_proofs([trust("root", "a", 5), trust("a", "b", 9)]);
+
+        let trusted = wot.trust_set();
+        assert_eq!(trusted.get("root"), Some(&u8::MAX));
+        assert_eq!(trusted.get("a"), Some(&5));
+        // Trust can only narrow as it's chained: min(5, 9) == 5, not 9.
+        assert_eq!(trusted.get("b"), Some(&5));
+    }
+
+    #[test]
+    fn trust_set_prefers_the_strongest_of_multiple_paths() {
+        let mut wot = WebOfTrust::new(vec!["root".to_string()], 10, ReviewPolicy::Warn);
+        wot.add_trust_proofs([
+            trust("root", "a", 3),
+            trust("a", "c", 3),
+            trust("root", "b", 9),
+            trust("b", "c", 9),
+        ]);
+
+        assert_eq!(wot.trust_set().get("c"), Some(&9));
+    }
+
+    #[test]
+    fn trust_set_respects_max_depth() {
+        let mut wot = WebOfTrust::new(vec!["root".to_string()], 1, ReviewPolicy::Warn);
+        wot.add_trust_proofs([trust("root", "a", 5), trust("a", "b", 5)]);
+
+        let trusted = wot.trust_set();
+        assert!(trusted.contains_key("a"));
+        assert!(!trusted.contains_key("b"));
+    }
+
+    #[test]
+    fn classify_ignores_a_review_whose_digest_does_not_match() {
+        let mut wot = WebOfTrust::new(vec!["alice".to_string()], 10, ReviewPolicy::Warn);
+        wot.add_reviews([review("alice", "sha512-other", ReviewRating::Positive)]);
+
+        assert_eq!(
+            wot.classify("foo", "1.0.0", "sha512-actual"),
+            ReviewVerdict::Unreviewed
+        );
+    }
+
+    #[test]
+    fn classify_lets_a_negative_review_override_a_positive_one() {
+        let mut wot = WebOfTrust::new(
+            vec!["alice".to_string(), "bob".to_string()],
+            10,
+            ReviewPolicy::Warn,
+        );
+        wot.add_reviews([
+            review("alice", "sha512-actual", ReviewRating::Positive),
+            review("bob", "sha512-actual", ReviewRating::Negative),
+        ]);
+
+        assert_eq!(
+            wot.classify("foo", "1.0.0", "sha512-actual"),
+            ReviewVerdict::ReviewedNegative
+        );
+    }
+
+    #[test]
+    fn classify_fetched_never_matches_a_missing_integrity_against_a_blank_digest() {
+        let mut wot = WebOfTrust::new(vec!["alice".to_string()], 10, ReviewPolicy::Warn);
+        wot.add_reviews([review("alice", "", ReviewRating::Positive)]);
+
+        assert_eq!(
+            wot.classify_fetched("foo", "1.0.0", None),
+            ReviewVerdict::Unreviewed
+        );
+    }
+}